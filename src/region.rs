@@ -1,8 +1,30 @@
+use std::env;
 use std::fmt;
+use std::fs;
+use std::path::PathBuf;
 use std::str::{self, FromStr};
 
+use base32;
+use dirs;
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(feature = "serde")]
+use serde::de::{MapAccess, Visitor};
+#[cfg(feature = "serde")]
+use serde::ser::SerializeStruct;
+
 use error::{S3Result, S3Error};
 
+// Shared by every test module below that mutates process-global env vars
+// (`AWS_REGION`, `AWS_CONFIG_FILE`, ...), so they can't interleave and
+// observe each other's half-set state.
+#[cfg(test)]
+static ENV_LOCK: ::std::sync::Mutex<()> = ::std::sync::Mutex::new(());
+
+/// Alphabet/padding used to embed a custom region's name and endpoint inside
+/// the host component of an `s3://` URL, where `://`, `/` and `.` are illegal.
+const URL_HOST_ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: true };
+
 /// AWS S3 [region identifier](https://docs.aws.amazon.com/general/latest/gr/rande.html#s3_region),
 /// passing in custom values is also possible, in that case it is up to you to pass a valid endpoint,
 /// otherwise boom will happen :)
@@ -57,7 +79,19 @@ pub enum Region {
     /// Digital Ocean sgp1
     DoSgp1,
     /// Custom region
-    Custom(String),
+    Custom { region: String, endpoint: String },
+}
+
+/// Where the bucket name goes when building a request URL: in the hostname
+/// (virtual-hosted) or in the path (path-style). See
+/// [`Region::default_addressing_style`] and [`Region::bucket_host`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressingStyle {
+    /// `https://bucket.host/key`, relying on wildcard DNS for the endpoint.
+    VirtualHost,
+    /// `https://host/bucket/key`, for endpoints that don't support
+    /// wildcard vhost DNS (MinIO, Ceph, localstack, most custom endpoints).
+    Path,
 }
 
 impl fmt::Display for Region {
@@ -82,38 +116,273 @@ impl fmt::Display for Region {
             DoNyc3 => write!(f, "nyc3"),
             DoAms3 => write!(f, "ams3"),
             DoSgp1 => write!(f, "sgp1"),
-            Custom(ref _endpoint) => write!(f, "custom")
+            Custom { ref region, .. } => write!(f, "{}", region)
         }
     }
 }
 
+#[cfg(test)]
+mod display_tests {
+    use super::*;
+
+    #[test]
+    fn custom_region_displays_as_its_region_name_not_the_endpoint() {
+        let region = Region::Custom {
+            region: "ceph-rgw".to_string(),
+            endpoint: "https://ceph.example.com".to_string()
+        };
+        // This is what feeds the SigV4 credential scope, so it must be the
+        // signing region name, not the endpoint and not the literal "custom".
+        assert_eq!(region.to_string(), "ceph-rgw");
+    }
+}
+
+/// Matches `s` against the known named regions only; `None` for anything
+/// else, including malformed or empty input. Unlike `FromStr`, this never
+/// falls back to a `Custom` region; `FromStr` builds on it and supplies that
+/// fallback itself.
+fn parse_named_region(s: &str) -> Option<Region> {
+    use self::Region::*;
+    match s {
+        "us-east-1" => Some(UsEast1),
+        "us-east-2" => Some(UsEast2),
+        "us-west-1" => Some(UsWest1),
+        "us-west-2" => Some(UsWest2),
+        "ca-central-1" => Some(CaCentral1),
+        "ap-south-1" => Some(ApSouth1),
+        "ap-northeast-1" => Some(ApNortheast1),
+        "ap-northeast-2" => Some(ApNortheast2),
+        "ap-southeast-1" => Some(ApSoutheast1),
+        "ap-southeast-2" => Some(ApSoutheast2),
+        "eu-central-1" => Some(EuCentral1),
+        "eu-west-1" => Some(EuWest1),
+        "eu-west-2" => Some(EuWest2),
+        "eu-west-3" => Some(EuWest3),
+        "sa-east-1" => Some(SaEast1),
+        "nyc3" => Some(DoNyc3),
+        "ams3" => Some(DoAms3),
+        "sgp1" => Some(DoSgp1),
+        _ => None
+    }
+}
+
 impl FromStr for Region {
     type Err = S3Error;
 
     fn from_str(s: &str) -> S3Result<Self> {
-        use self::Region::*;
-        match s {
-            "us-east-1" => Ok(UsEast1),
-            "us-east-2" => Ok(UsEast2),
-            "us-west-1" => Ok(UsWest1),
-            "us-west-2" => Ok(UsWest2),
-            "ca-central-1" => Ok(CaCentral1),
-            "ap-south-1" => Ok(ApSouth1),
-            "ap-northeast-1" => Ok(ApNortheast1),
-            "ap-northeast-2" => Ok(ApNortheast2),
-            "ap-southeast-1" => Ok(ApSoutheast1),
-            "ap-southeast-2" => Ok(ApSoutheast2),
-            "eu-central-1" => Ok(EuCentral1),
-            "eu-west-1" => Ok(EuWest1),
-            "eu-west-2" => Ok(EuWest2),
-            "eu-west-3" => Ok(EuWest3),
-            "sa-east-1" => Ok(SaEast1),
-            "nyc3" => Ok(DoNyc3),
-            "ams3" => Ok(DoAms3),
-            "sgp1" => Ok(DoSgp1),
-            x => Ok(Custom(x.to_string()))
+        Ok(parse_named_region(s).unwrap_or_else(|| Region::Custom {
+            region: s.to_string(),
+            endpoint: s.to_string()
+        }))
+    }
+}
+
+/// Parses `s` via `FromStr`, except empty input (an unset-but-present env
+/// var or config value) resolves to `None` rather than an unusable
+/// empty-host `Custom` region.
+fn parse_region_or_custom(s: &str) -> Option<Region> {
+    if s.trim().is_empty() {
+        None
+    } else {
+        Some(s.parse().expect("Region::from_str is infallible"))
+    }
+}
+
+impl Default for Region {
+    /// Resolves a region the way the AWS SDKs do: `AWS_REGION`, then
+    /// `AWS_DEFAULT_REGION`, then the `region` key of the active profile in
+    /// `~/.aws/config`, falling back to `us-east-1` if none of those are set
+    /// or only an empty value is found. A value that isn't one of the named
+    /// regions resolves to a `Custom` region rather than `us-east-1`, so
+    /// pointing `AWS_REGION` at a non-AWS signing region still works.
+    fn default() -> Region {
+        if let Ok(region) = env::var("AWS_REGION") {
+            if let Some(region) = parse_region_or_custom(&region) {
+                return region;
+            }
+        }
+
+        if let Ok(region) = env::var("AWS_DEFAULT_REGION") {
+            if let Some(region) = parse_region_or_custom(&region) {
+                return region;
+            }
+        }
+
+        region_from_config_file().unwrap_or(Region::UsEast1)
+    }
+}
+
+/// Path to the AWS config file, honoring `AWS_CONFIG_FILE` and defaulting to
+/// `~/.aws/config`.
+fn aws_config_file() -> Option<PathBuf> {
+    if let Ok(path) = env::var("AWS_CONFIG_FILE") {
+        return Some(PathBuf::from(path));
+    }
+
+    dirs::home_dir().map(|home| home.join(".aws").join("config"))
+}
+
+/// Reads the `region` key of the active profile (`AWS_PROFILE`, defaulting to
+/// `default`) out of the AWS config file, if present and well-formed.
+fn region_from_config_file() -> Option<Region> {
+    let path = aws_config_file()?;
+    let contents = fs::read_to_string(path).ok()?;
+
+    let profile = env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+    let section = if profile == "default" {
+        "[default]".to_string()
+    } else {
+        format!("[profile {}]", profile)
+    };
+
+    let mut in_section = false;
+    for line in contents.lines() {
+        let line = line.trim();
+
+        if line.starts_with('[') {
+            in_section = line == section;
+            continue;
+        }
+
+        if !in_section {
+            continue;
+        }
+
+        if let Some(pos) = line.find('=') {
+            let key = line[..pos].trim();
+            let value = line[pos + 1..].trim();
+            if key == "region" {
+                if let Some(region) = parse_region_or_custom(value) {
+                    return Some(region);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod config_file_tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    fn with_config_file<F: FnOnce()>(contents: &str, f: F) {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let path = env::temp_dir().join(format!("rust-s3-test-aws-config-{}", process::id()));
+        fs::write(&path, contents).unwrap();
+        env::set_var("AWS_CONFIG_FILE", &path);
+
+        f();
+
+        env::remove_var("AWS_CONFIG_FILE");
+        env::remove_var("AWS_PROFILE");
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reads_default_profile() {
+        with_config_file("[default]\nregion = eu-west-2\n", || {
+            assert_eq!(region_from_config_file(), Some(Region::EuWest2));
+        });
+    }
+
+    #[test]
+    fn honors_aws_profile() {
+        let contents = "[default]\nregion = us-east-1\n\n[profile staging]\nregion = ap-south-1\n";
+        with_config_file(contents, || {
+            env::set_var("AWS_PROFILE", "staging");
+            assert_eq!(region_from_config_file(), Some(Region::ApSouth1));
+        });
+    }
+
+    #[test]
+    fn returns_none_for_missing_profile() {
+        with_config_file("[profile other]\nregion = eu-west-2\n", || {
+            assert_eq!(region_from_config_file(), None);
+        });
+    }
+
+    #[test]
+    fn returns_none_for_empty_region_value() {
+        with_config_file("[default]\nregion = \n", || {
+            assert_eq!(region_from_config_file(), None);
+        });
+    }
+
+    #[test]
+    fn returns_none_when_config_file_is_missing() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        env::set_var("AWS_CONFIG_FILE", env::temp_dir().join("rust-s3-test-aws-config-missing"));
+        assert_eq!(region_from_config_file(), None);
+        env::remove_var("AWS_CONFIG_FILE");
+    }
+
+    #[test]
+    fn resolves_unrecognized_region_to_custom() {
+        with_config_file("[default]\nregion = my-ceph-region\n", || {
+            assert_eq!(region_from_config_file(), Some(Region::Custom {
+                region: "my-ceph-region".to_string(),
+                endpoint: "my-ceph-region".to_string()
+            }));
+        });
+    }
+}
+
+#[cfg(test)]
+mod default_tests {
+    use super::*;
+
+    fn with_env<F: FnOnce()>(vars: &[(&str, &str)], f: F) {
+        let _guard = ENV_LOCK.lock().unwrap();
+        for (key, value) in vars {
+            env::set_var(key, value);
+        }
+
+        f();
+
+        for (key, _) in vars {
+            env::remove_var(key);
         }
     }
+
+    #[test]
+    fn aws_region_takes_precedence_and_allows_custom_values() {
+        with_env(&[("AWS_REGION", "my-ceph-region"), ("AWS_DEFAULT_REGION", "eu-west-1")], || {
+            assert_eq!(Region::default(), Region::Custom {
+                region: "my-ceph-region".to_string(),
+                endpoint: "my-ceph-region".to_string()
+            });
+        });
+    }
+
+    #[test]
+    fn falls_back_to_aws_default_region_when_aws_region_is_unset() {
+        with_env(&[("AWS_DEFAULT_REGION", "eu-west-2")], || {
+            env::remove_var("AWS_REGION");
+            assert_eq!(Region::default(), Region::EuWest2);
+        });
+    }
+
+    #[test]
+    fn falls_back_to_us_east_1_when_nothing_is_set() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        env::remove_var("AWS_REGION");
+        env::remove_var("AWS_DEFAULT_REGION");
+        // Point at a missing file so a real ~/.aws/config can't supply a region.
+        env::set_var("AWS_CONFIG_FILE", env::temp_dir().join("rust-s3-test-aws-config-missing"));
+        env::remove_var("AWS_PROFILE");
+        assert_eq!(Region::default(), Region::UsEast1);
+        env::remove_var("AWS_CONFIG_FILE");
+    }
+
+    #[test]
+    fn empty_aws_region_falls_through_to_aws_default_region() {
+        with_env(&[("AWS_REGION", ""), ("AWS_DEFAULT_REGION", "ap-south-1")], || {
+            assert_eq!(Region::default(), Region::ApSouth1);
+        });
+    }
 }
 
 impl Region {
@@ -140,15 +409,15 @@ impl Region {
             DoNyc3 => "nyc3.digitaloceanspaces.com",
             DoAms3 => "ams3.digitaloceanspaces.com",
             DoSgp1 => "sgp1.digitaloceanspaces.com",
-            Custom(ref endpoint) => endpoint
+            Custom { ref endpoint, .. } => endpoint
         }
     }
 
     pub fn scheme(&self) -> &str {
         match *self {
-            Region::Custom(ref s) => {
-                match s.find("://") {
-                    Some(pos) => &s[..pos],
+            Region::Custom { ref endpoint, .. } => {
+                match endpoint.find("://") {
+                    Some(pos) => &endpoint[..pos],
                     None => "https"
                 }
             },
@@ -158,13 +427,212 @@ impl Region {
 
     pub fn host(&self) -> &str {
         match *self {
-            Region::Custom(ref s) => {
-                match s.find("://") {
-                    Some(pos) => &s[pos + 3..],
-                    None => &s
+            Region::Custom { ref endpoint, .. } => {
+                match endpoint.find("://") {
+                    Some(pos) => &endpoint[pos + 3..],
+                    None => endpoint
                 }
             },
             _ => self.endpoint()
         }
     }
+
+    /// Virtual-hosted for named regions, path-style for custom ones.
+    pub fn default_addressing_style(&self) -> AddressingStyle {
+        match *self {
+            Region::Custom { .. } => AddressingStyle::Path,
+            _ => AddressingStyle::VirtualHost
+        }
+    }
+
+    /// Host component of a bucket URL, with the bucket placed per `style`.
+    pub fn bucket_host(&self, bucket: &str, style: AddressingStyle) -> String {
+        match style {
+            AddressingStyle::VirtualHost => format!("{}.{}", bucket, self.host()),
+            AddressingStyle::Path => self.host().to_string()
+        }
+    }
+
+    /// Encodes this region as a valid `s3://` URL host, base32-ing the
+    /// region/endpoint of a `Custom` region to dodge illegal host characters.
+    pub fn to_url_host(&self) -> String {
+        match *self {
+            Region::Custom { ref region, ref endpoint } => format!(
+                "{}+{}",
+                base32::encode(URL_HOST_ALPHABET, region.as_bytes()),
+                base32::encode(URL_HOST_ALPHABET, endpoint.as_bytes())
+            ),
+            _ => self.to_string()
+        }
+    }
+
+    /// Inverse of [`to_url_host`](Region::to_url_host).
+    pub fn from_url_host(s: &str) -> S3Result<Region> {
+        match s.find('+') {
+            None => s.parse(),
+            Some(pos) => {
+                let region = base32::decode(URL_HOST_ALPHABET, &s[..pos])
+                    .ok_or_else(|| S3Error::from("Invalid base32 region in s3:// URL host"))?;
+                let endpoint = base32::decode(URL_HOST_ALPHABET, &s[pos + 1..])
+                    .ok_or_else(|| S3Error::from("Invalid base32 endpoint in s3:// URL host"))?;
+                Ok(Region::Custom {
+                    region: String::from_utf8(region)
+                        .map_err(|_| S3Error::from("Region in s3:// URL host is not valid UTF-8"))?,
+                    endpoint: String::from_utf8(endpoint)
+                        .map_err(|_| S3Error::from("Endpoint in s3:// URL host is not valid UTF-8"))?
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod url_host_tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_named_region() {
+        let region = Region::EuWest1;
+        assert_eq!(region.to_url_host(), "eu-west-1");
+        assert_eq!(Region::from_url_host("eu-west-1").unwrap(), region);
+    }
+
+    #[test]
+    fn round_trips_custom_region() {
+        let region = Region::Custom {
+            region: "ceph".to_string(),
+            endpoint: "https://ceph.example.com".to_string()
+        };
+        let host = region.to_url_host();
+        assert!(host.contains('+'));
+        assert_eq!(Region::from_url_host(&host).unwrap(), region);
+    }
+
+    #[test]
+    fn from_url_host_without_separator_falls_back_to_from_str() {
+        let region = Region::from_url_host("some-private-endpoint").unwrap();
+        assert_eq!(region, Region::Custom {
+            region: "some-private-endpoint".to_string(),
+            endpoint: "some-private-endpoint".to_string()
+        });
+    }
+
+    #[test]
+    fn from_url_host_rejects_garbled_base32_halves() {
+        assert!(Region::from_url_host("not-valid-base32!+also-invalid!").is_err());
+    }
+
+    #[test]
+    fn from_url_host_rejects_non_utf8_decoded_bytes() {
+        // Valid base32, but decodes to the invalid UTF-8 byte sequence 0xFF 0xFE.
+        let garbage = base32::encode(URL_HOST_ALPHABET, &[0xFF, 0xFE]);
+        let host = format!("{}+{}", garbage, garbage);
+        assert!(Region::from_url_host(&host).is_err());
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Region {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match *self {
+            Region::Custom { ref region, ref endpoint } => {
+                let mut state = serializer.serialize_struct("Region", 2)?;
+                state.serialize_field("region", region)?;
+                state.serialize_field("endpoint", endpoint)?;
+                state.end()
+            },
+            _ => serializer.serialize_str(&self.to_string())
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Region {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RegionVisitor;
+
+        impl<'de> Visitor<'de> for RegionVisitor {
+            type Value = Region;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a region name string, or a map with `region` and `endpoint` keys")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Region, E>
+            where
+                E: de::Error,
+            {
+                // `FromStr` never fails: an unrecognized string becomes a
+                // custom region rather than erroring, so private endpoints
+                // keep working without a library update.
+                v.parse().map_err(de::Error::custom)
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Region, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut region = None;
+                let mut endpoint = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "region" => region = Some(map.next_value()?),
+                        "endpoint" => endpoint = Some(map.next_value()?),
+                        _ => { map.next_value::<de::IgnoredAny>()?; }
+                    }
+                }
+
+                Ok(Region::Custom {
+                    region: region.ok_or_else(|| de::Error::missing_field("region"))?,
+                    endpoint: endpoint.ok_or_else(|| de::Error::missing_field("endpoint"))?
+                })
+            }
+        }
+
+        deserializer.deserialize_any(RegionVisitor)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod serde_tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn named_region_round_trips_as_its_canonical_string() {
+        let json = serde_json::to_string(&Region::EuWest1).unwrap();
+        assert_eq!(json, "\"eu-west-1\"");
+        assert_eq!(serde_json::from_str::<Region>(&json).unwrap(), Region::EuWest1);
+    }
+
+    #[test]
+    fn custom_region_round_trips_as_a_struct() {
+        let region = Region::Custom {
+            region: "ceph-rgw".to_string(),
+            endpoint: "https://ceph.example.com".to_string()
+        };
+        let json = serde_json::to_string(&region).unwrap();
+        assert_eq!(serde_json::from_str::<Region>(&json).unwrap(), region);
+    }
+
+    #[test]
+    fn unknown_string_deserializes_through_from_str_to_custom() {
+        let region: Region = serde_json::from_str("\"my-private-endpoint\"").unwrap();
+        assert_eq!(region, Region::Custom {
+            region: "my-private-endpoint".to_string(),
+            endpoint: "my-private-endpoint".to_string()
+        });
+    }
+
+    #[test]
+    fn missing_field_in_struct_form_errors() {
+        let result: Result<Region, _> = serde_json::from_str("{\"region\": \"ceph-rgw\"}");
+        assert!(result.is_err());
+    }
 }